@@ -7,7 +7,7 @@ use image::{
 use nalgebra::{vector, EuclideanNorm, Norm};
 use ndarray::{Array, Array1};
 use rayon::prelude::*;
-use std::{cmp, f32::consts::PI, ops::RangeInclusive, path::PathBuf};
+use std::{cmp, collections::HashMap, f32::consts::PI, path::PathBuf};
 
 #[derive(Debug, Clone, Parser)]
 #[clap(author, version, about, long_about = None)]
@@ -40,12 +40,45 @@ struct Opt {
     #[clap(long, requires = "use_csv")]
     /// Include a CSV header line. If --write-coords then the header line is `x1, y1, x2, y2`, otherwise `pins`
     header: bool,
+    #[clap(long, default_value = "30")]
+    /// Darkness subtracted from a pixel's remaining brightness (out of 255) each time a thread
+    /// covers it, instead of zeroing it outright. Lower values need more overlapping passes to
+    /// fully darken a region, matching how real thread builds density
+    opacity: u8,
+    #[clap(long, default_value = "1.0")]
+    /// Width of a thread, in pixels. Modeled as a stroked band with round caps at the pins,
+    /// sampled both when scoring candidate lines and when rasterizing the preview image, so the
+    /// two agree. Fractional values are allowed to calibrate against real thread gauge. Widths
+    /// of 1 pixel or less are all drawn as the same anti-aliased 1-pixel line, without caps
+    thread_width: f64,
+    #[clap(long)]
+    /// Write a vector SVG tracing the thread path, suitable for plotters and laser cutters
+    svg: bool,
+    #[clap(long, default_value = "0.5", requires = "svg")]
+    /// Stroke width of the SVG thread path, in pixels
+    svg_stroke_width: f64,
+    #[clap(long, default_value = "0.6", requires = "svg")]
+    /// Stroke opacity of the SVG thread path, between 0 and 1
+    svg_opacity: f32,
+    #[clap(long, requires = "svg")]
+    /// Draw numbered pin markers around the loom circle in the SVG output
+    svg_pin_markers: bool,
+    #[clap(long)]
+    /// Don't auto-rotate/flip the target image according to its EXIF orientation tag
+    no_auto_orient: bool,
 }
 
 fn main() {
     let opt = Opt::parse();
 
-    let img = image::open(&opt.path).expect("Couldn't load target image");
+    let bytes = std::fs::read(&opt.path).expect("Couldn't read target image");
+    let mut img = image::load_from_memory(&bytes).expect("Couldn't load target image");
+
+    if !opt.no_auto_orient {
+        if let Some(orientation) = exif_orientation(&bytes) {
+            img = apply_orientation(img, orientation);
+        }
+    }
 
     let min_edge = img.width().min(img.height());
     let radius = opt.radius.map_or(min_edge, |radius| radius.min(min_edge));
@@ -64,17 +97,103 @@ fn main() {
     let mut outfile = opt.clone().output.unwrap_or(opt.clone().path);
 
     if !opt.no_img {
-        write_img(&mut outfile, &prefix, &thread_coords, length);
+        write_img(&mut outfile, &prefix, &thread_coords, length, &opt);
     }
 
     if opt.csv {
         write_csv(&mut outfile, &prefix, &thread_coords, &opt);
     }
+
+    if opt.svg {
+        write_svg(&mut outfile, &prefix, &thread_coords, radius, &opt);
+    }
 }
 
-fn thread(mut img: GrayImage, radius: u32, opt: &Opt) -> Vec<(Array1<f64>, Array1<f64>, usize)> {
-    let num_pins = opt.pins + 1;
-    let loom = Array::linspace(0., 2. * PI, num_pins)
+fn exif_orientation(bytes: &[u8]) -> Option<u8> {
+    if bytes.len() < 4 || bytes[0..2] != [0xFF, 0xD8] {
+        return None;
+    }
+    let mut pos = 2;
+    while pos + 4 <= bytes.len() {
+        if bytes[pos] != 0xFF {
+            break;
+        }
+        let marker = bytes[pos + 1];
+        if marker == 0xD8 || marker == 0xD9 {
+            pos += 2;
+            continue;
+        }
+        if marker == 0xDA {
+            break; // start of scan: no more metadata markers follow
+        }
+        let seg_len = usize::from(u16::from_be_bytes([bytes[pos + 2], bytes[pos + 3]]));
+        if seg_len < 2 {
+            return None;
+        }
+        let data_start = pos + 4;
+        let data_end = pos + 2 + seg_len;
+        if data_end > bytes.len() {
+            return None;
+        }
+        if marker == 0xE1 && bytes[data_start..data_end].starts_with(b"Exif\0\0") {
+            return parse_tiff_orientation(&bytes[data_start + 6..data_end]);
+        }
+        pos = data_end;
+    }
+    None
+}
+
+/// Reads the IFD0 orientation tag (0x0112) out of a TIFF header, respecting its byte order.
+fn parse_tiff_orientation(tiff: &[u8]) -> Option<u8> {
+    let little_endian = match tiff.get(0..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+    let read_u16 = |b: &[u8]| -> u16 {
+        if little_endian {
+            u16::from_le_bytes([b[0], b[1]])
+        } else {
+            u16::from_be_bytes([b[0], b[1]])
+        }
+    };
+    let read_u32 = |b: &[u8]| -> u32 {
+        if little_endian {
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+        } else {
+            u32::from_be_bytes([b[0], b[1], b[2], b[3]])
+        }
+    };
+
+    #[allow(clippy::cast_possible_truncation)]
+    let ifd_offset = read_u32(tiff.get(4..8)?) as usize;
+    let num_entries = usize::from(read_u16(tiff.get(ifd_offset..ifd_offset + 2)?));
+    for i in 0..num_entries {
+        let entry = tiff.get(ifd_offset + 2 + i * 12..ifd_offset + 14 + i * 12)?;
+        let tag = read_u16(&entry[0..2]);
+        if tag == 0x0112 {
+            #[allow(clippy::cast_possible_truncation)]
+            return Some(read_u16(&entry[8..10]) as u8);
+        }
+    }
+    None
+}
+
+fn apply_orientation(img: DynamicImage, orientation: u8) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.fliph().rotate270(),
+        6 => img.rotate90(),
+        7 => img.fliph().rotate90(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+fn loom_positions(radius: u32, num_pins: usize) -> Vec<nalgebra::Vector2<f64>> {
+    Array::linspace(0., 2. * PI, num_pins)
         .into_iter()
         .map(|alpha| {
             vector![
@@ -82,7 +201,12 @@ fn thread(mut img: GrayImage, radius: u32, opt: &Opt) -> Vec<(Array1<f64>, Array
                 f64::from(radius) * f64::from(1. + alpha.sin())
             ]
         })
-        .collect::<Vec<_>>();
+        .collect()
+}
+
+fn thread(mut img: GrayImage, radius: u32, opt: &Opt) -> Vec<(Array1<f64>, Array1<f64>, usize)> {
+    let num_pins = opt.pins + 1;
+    let loom = loom_positions(radius, num_pins);
 
     let mut threads = Vec::with_capacity(opt.threads);
     let mut prev_pins = [0; 2];
@@ -106,20 +230,16 @@ fn thread(mut img: GrayImage, radius: u32, opt: &Opt) -> Vec<(Array1<f64>, Array
                 let dist = EuclideanNorm.metric_distance(prev_pos, next_pos) as usize;
                 let x_line = Array::linspace(prev_pos[0], next_pos[0], dist);
                 let y_line = Array::linspace(prev_pos[1], next_pos[1], dist);
-                #[allow(clippy::cast_sign_loss)] // coordinates are positive
-                #[allow(clippy::cast_possible_truncation)]
-                // truncation is desired
-                let line_sum = x_line
-                    .iter()
-                    .zip(y_line.iter())
-                    .map(|(&x, &y)| {
-                        let pixel_idx = pos_to_pixel_idx(x, y, &img);
-                        u32::from(
-                            // XXX: To change to `get_pixel_unchecked` once image v0.24 lands
-                            unsafe { img.get_unchecked(pixel_idx) }[0],
-                        )
-                    })
-                    .sum();
+                let line_sum = stroke_coverage(
+                    prev_pos[0],
+                    prev_pos[1],
+                    next_pos[0],
+                    next_pos[1],
+                    opt.thread_width,
+                )
+                .iter()
+                .map(|&(x, y, coverage)| coverage * pixel_coverage_value(&img, x, y))
+                .sum();
                 Line::new(current_pin, x_line, y_line, line_sum)
             })
             .max()
@@ -128,13 +248,15 @@ fn thread(mut img: GrayImage, radius: u32, opt: &Opt) -> Vec<(Array1<f64>, Array
         prev_pins = [prev_pins[1], best.dest_pin];
 
         threads.push((best.xs.clone(), best.ys.clone(), best.dest_pin));
-        #[allow(clippy::cast_sign_loss)] // coordinates are positive
-        #[allow(clippy::cast_possible_truncation)] // truncation is desired
-        best.xs.into_iter().zip(best.ys).for_each(|(x, y)| {
-            let pixel_idx = pos_to_pixel_idx(x, y, &img);
-            let pixel = unsafe { img.get_unchecked_mut(pixel_idx) };
-            pixel[0] = 0
-        });
+        for (x, y, coverage) in stroke_coverage(
+            best.xs[0],
+            best.ys[0],
+            best.xs[best.xs.len() - 1],
+            best.ys[best.ys.len() - 1],
+            opt.thread_width,
+        ) {
+            darken_pixel(&mut img, x, y, coverage * f32::from(opt.opacity));
+        }
 
         if best.dest_pin == prev_pin {
             break;
@@ -143,9 +265,134 @@ fn thread(mut img: GrayImage, radius: u32, opt: &Opt) -> Vec<(Array1<f64>, Array
     threads
 }
 
-fn pos_to_pixel_idx<I: GenericImageView>(x: f64, y: f64, img: &I) -> RangeInclusive<usize> {
-    let min_idx = y.floor() as usize * img.width() as usize + x.floor() as usize;
-    min_idx..=min_idx
+fn wu_line(x0: f64, y0: f64, x1: f64, y1: f64) -> Vec<(i64, i64, f32)> {
+    let (dx, dy) = (x1 - x0, y1 - y0);
+    let steep = dy.abs() > dx.abs();
+
+    let (x0, y0, x1, y1) = if steep { (y0, x0, y1, x1) } else { (x0, y0, x1, y1) };
+    let (x0, y0, x1, y1) = if x0 > x1 { (x1, y1, x0, y0) } else { (x0, y0, x1, y1) };
+
+    let (dx, dy) = (x1 - x0, y1 - y0);
+    #[allow(clippy::cast_precision_loss)]
+    let gradient = if dx == 0. { 1. } else { dy / dx };
+
+    #[allow(clippy::cast_sign_loss)]
+    #[allow(clippy::cast_possible_truncation)]
+    let mut points = Vec::with_capacity(2 * (x1 - x0).round() as usize + 2);
+    let mut intery = y0;
+    let mut x = x0;
+    while x <= x1 {
+        let y_floor = intery.floor();
+        let frac = (intery - y_floor) as f32;
+        #[allow(clippy::cast_possible_truncation)]
+        let (x_i, y_i) = (x.round() as i64, y_floor as i64);
+        let (lo, hi) = if steep {
+            ((y_i, x_i), (y_i + 1, x_i))
+        } else {
+            ((x_i, y_i), (x_i, y_i + 1))
+        };
+        points.push((lo.0, lo.1, 1. - frac));
+        points.push((hi.0, hi.1, frac));
+        intery += gradient;
+        x += 1.;
+    }
+    points
+}
+
+fn stroke_coverage(x0: f64, y0: f64, x1: f64, y1: f64, width: f64) -> Vec<(i64, i64, f32)> {
+    let half_width = (width / 2.).max(0.);
+    if half_width <= 0.5 {
+        return wu_line(x0, y0, x1, y1);
+    }
+
+    let (dx, dy) = (x1 - x0, y1 - y0);
+    let len = (dx * dx + dy * dy).sqrt();
+    if len < f64::EPSILON {
+        return wu_line(x0, y0, x1, y1);
+    }
+    let (ux, uy) = (dx / len, dy / len);
+    let (nx, ny) = (-uy, ux);
+
+    // Sample the perpendicular/cap offsets on a sub-pixel grid rather than whole-pixel steps:
+    // widths in (1px, 2px] would otherwise only ever hit offset 0 and collapse onto the bare
+    // centerline, making --thread-width a no-op over exactly the range users calibrate with.
+    const OFFSET_STEP: f64 = 0.25;
+    #[allow(clippy::cast_possible_truncation)]
+    let offset_samples = (half_width / OFFSET_STEP).ceil() as i64;
+    #[allow(clippy::cast_precision_loss)]
+    let offsets: Vec<f64> = (-offset_samples..=offset_samples)
+        .map(|step| step as f64 * OFFSET_STEP)
+        .filter(|offset| offset.abs() <= half_width)
+        .collect();
+
+    // The perpendicular fan and the two end caps can land on the same pixel (short segments
+    // relative to thread_width); key by pixel and keep the strongest coverage instead of
+    // summing samples, so a single stroke never reports more than one pass worth of coverage.
+    let mut coverage_by_pixel: HashMap<(i64, i64), f32> = HashMap::new();
+    let mut accumulate = |px: i64, py: i64, coverage: f32| {
+        let entry = coverage_by_pixel.entry((px, py)).or_insert(0.);
+        *entry = entry.max(coverage);
+    };
+
+    for (cx, cy, along_coverage) in wu_line(x0, y0, x1, y1).into_iter().map(|(x, y, c)| {
+        #[allow(clippy::cast_precision_loss)]
+        (x as f64, y as f64, c)
+    }) {
+        for &offset in &offsets {
+            let radial_falloff = (1. - offset.abs() / half_width).max(0.) as f32;
+            if radial_falloff <= 0. {
+                continue;
+            }
+            #[allow(clippy::cast_possible_truncation)]
+            let (px, py) = ((cx + nx * offset).round() as i64, (cy + ny * offset).round() as i64);
+            accumulate(px, py, along_coverage * radial_falloff);
+        }
+    }
+
+    // Round caps: fan out a half-disc of coverage past each endpoint, on the same grid.
+    for &t in offsets.iter().filter(|offset| **offset > 0.) {
+        for (ex, ey) in [(x0 - ux * t, y0 - uy * t), (x1 + ux * t, y1 + uy * t)] {
+            for &offset in &offsets {
+                let dist = (t * t + offset * offset).sqrt();
+                let radial_falloff = (1. - dist / half_width).max(0.) as f32;
+                if radial_falloff <= 0. {
+                    continue;
+                }
+                #[allow(clippy::cast_possible_truncation)]
+                let (px, py) = (
+                    (ex + nx * offset).round() as i64,
+                    (ey + ny * offset).round() as i64,
+                );
+                accumulate(px, py, radial_falloff);
+            }
+        }
+    }
+
+    coverage_by_pixel
+        .into_iter()
+        .map(|((x, y), coverage)| (x, y, coverage))
+        .collect()
+}
+
+/// Reads a pixel's brightness as coverage-weightable `f32`, or `0.` if out of bounds.
+fn pixel_coverage_value(img: &GrayImage, x: i64, y: i64) -> f32 {
+    if x < 0 || y < 0 || x as u32 >= img.width() || y as u32 >= img.height() {
+        return 0.;
+    }
+    #[allow(clippy::cast_sign_loss)]
+    f32::from(img.get_pixel(x as u32, y as u32)[0])
+}
+
+/// Subtracts `amount` from a pixel's brightness, clamped at 0, if the coordinate is in bounds.
+fn darken_pixel(img: &mut GrayImage, x: i64, y: i64, amount: f32) {
+    if x < 0 || y < 0 || x as u32 >= img.width() || y as u32 >= img.height() {
+        return;
+    }
+    #[allow(clippy::cast_sign_loss)]
+    let pixel = img.get_pixel_mut(x as u32, y as u32);
+    #[allow(clippy::cast_possible_truncation)]
+    let amount = amount.round() as u8;
+    pixel[0] = pixel[0].saturating_sub(amount);
 }
 
 #[derive(Clone)]
@@ -153,11 +400,11 @@ struct Line {
     dest_pin: usize,
     xs: Array1<f64>,
     ys: Array1<f64>,
-    sum: u32,
+    sum: f32,
 }
 
 impl Line {
-    pub fn new(dest_pin: usize, xs: Array1<f64>, ys: Array1<f64>, sum: u32) -> Self {
+    pub fn new(dest_pin: usize, xs: Array1<f64>, ys: Array1<f64>, sum: f32) -> Self {
         Self {
             dest_pin,
             xs,
@@ -177,7 +424,7 @@ impl PartialEq<Line> for Line {
 
 impl Ord for Line {
     fn cmp(&self, other: &Self) -> cmp::Ordering {
-        self.sum.cmp(&other.sum)
+        self.sum.partial_cmp(&other.sum).unwrap_or(cmp::Ordering::Equal)
     }
 }
 
@@ -215,16 +462,20 @@ fn write_img(
     prefix: &str,
     thread_coords: &[(Array1<f64>, Array1<f64>, usize)],
     length: u32,
+    opt: &Opt,
 ) {
     let mut img_threaded = GrayImage::from_pixel(length, length, Luma([255]));
     for (x_line, y_line, _) in thread_coords {
-        #[allow(clippy::cast_sign_loss)] // coordinates are positive
-        #[allow(clippy::cast_possible_truncation)] // truncation is desired
-        x_line.into_iter().zip(y_line).for_each(|(&x, &y)| {
-            let pixel_idx = pos_to_pixel_idx(x, y, &img_threaded);
-            let pixel = unsafe { img_threaded.get_unchecked_mut(pixel_idx) };
-            pixel[0] = 0;
-        });
+        let last = x_line.len() - 1;
+        for (x, y, coverage) in stroke_coverage(
+            x_line[0],
+            y_line[0],
+            x_line[last],
+            y_line[last],
+            opt.thread_width,
+        ) {
+            darken_pixel(&mut img_threaded, x, y, coverage * 255.);
+        }
     }
     outfile.set_file_name(format!("{}_threaded", prefix));
     outfile.set_extension("png");
@@ -233,6 +484,53 @@ fn write_img(
         .expect("Failed to save threaded image");
 }
 
+fn write_svg(
+    outfile: &mut PathBuf,
+    prefix: &str,
+    thread_coords: &[(Array1<f64>, Array1<f64>, usize)],
+    radius: u32,
+    opt: &Opt,
+) {
+    let diameter = f64::from(radius) * 2.;
+
+    let mut path_data = String::new();
+    if let Some((x_line, y_line, _)) = thread_coords.first() {
+        path_data.push_str(&format!("M{:.2},{:.2} ", x_line[0], y_line[0]));
+    }
+    for (x_line, y_line, _) in thread_coords {
+        let last = x_line.len() - 1;
+        path_data.push_str(&format!("L{:.2},{:.2} ", x_line[last], y_line[last]));
+    }
+
+    let mut markers = String::new();
+    if opt.svg_pin_markers {
+        let num_pins = opt.pins + 1;
+        for (pin, pos) in loom_positions(radius, num_pins).iter().enumerate() {
+            markers.push_str(&format!(
+                "<text x=\"{:.2}\" y=\"{:.2}\" font-size=\"4\" text-anchor=\"middle\">{}</text>\n",
+                pos[0], pos[1], pin
+            ));
+        }
+    }
+
+    let svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {diameter} {diameter}\">\n\
+         <circle cx=\"{r}\" cy=\"{r}\" r=\"{r}\" fill=\"none\" stroke=\"black\" stroke-width=\"0.5\"/>\n\
+         <path d=\"{path_data}\" fill=\"none\" stroke=\"black\" stroke-width=\"{stroke_width}\" stroke-opacity=\"{stroke_opacity}\"/>\n\
+         {markers}</svg>\n",
+        diameter = diameter,
+        r = radius,
+        path_data = path_data.trim_end(),
+        stroke_width = opt.svg_stroke_width,
+        stroke_opacity = opt.svg_opacity,
+        markers = markers,
+    );
+
+    outfile.set_file_name(format!("{}_threaded", prefix));
+    outfile.set_extension("svg");
+    std::fs::write(&outfile, svg).expect("Failed to save threaded SVG");
+}
+
 fn write_csv(
     out_dir: &mut PathBuf,
     prefix: &str,
@@ -286,4 +584,88 @@ mod tests {
     fn verify_app() {
         Opt::into_app().debug_assert();
     }
+
+    #[test]
+    fn wu_line_splits_coverage_between_straddling_pixels() {
+        let points = wu_line(0., 0., 3., 1.5);
+        let first_two = &points[0..2];
+        let coverage_sum: f32 = first_two.iter().map(|&(_, _, c)| c).sum();
+        assert!((coverage_sum - 1.).abs() < 1e-6);
+        assert_eq!(
+            first_two.iter().map(|&(x, y, _)| (x, y)).collect::<Vec<_>>(),
+            vec![(0, 0), (0, 1)]
+        );
+    }
+
+    fn distinct_rows(points: &[(i64, i64, f32)]) -> usize {
+        points.iter().map(|&(_, y, _)| y).collect::<std::collections::HashSet<_>>().len()
+    }
+
+    #[test]
+    fn stroke_coverage_widens_below_two_pixels() {
+        let thin = stroke_coverage(0., 50., 100., 50., 1.0);
+        let banded = stroke_coverage(0., 50., 100., 50., 1.5);
+        assert!(distinct_rows(&banded) > distinct_rows(&thin));
+    }
+
+    fn jpeg_with_exif_orientation(little_endian: bool, orientation: u16) -> Vec<u8> {
+        let mut tiff = Vec::new();
+        macro_rules! push_u16 {
+            ($v:expr) => {
+                if little_endian {
+                    tiff.extend_from_slice(&$v.to_le_bytes());
+                } else {
+                    tiff.extend_from_slice(&$v.to_be_bytes());
+                }
+            };
+        }
+        macro_rules! push_u32 {
+            ($v:expr) => {
+                if little_endian {
+                    tiff.extend_from_slice(&$v.to_le_bytes());
+                } else {
+                    tiff.extend_from_slice(&$v.to_be_bytes());
+                }
+            };
+        }
+
+        tiff.extend_from_slice(if little_endian { b"II" } else { b"MM" });
+        push_u16!(42u16);
+        push_u32!(8u32); // offset of IFD0
+        push_u16!(1u16); // one entry
+        push_u16!(0x0112u16); // orientation tag
+        push_u16!(3u16); // type SHORT
+        push_u32!(1u32); // count
+        push_u16!(orientation);
+        tiff.extend_from_slice(&[0, 0]); // pad value field to 4 bytes
+        push_u32!(0u32); // next IFD offset
+
+        let mut app1 = b"Exif\0\0".to_vec();
+        app1.extend_from_slice(&tiff);
+        #[allow(clippy::cast_possible_truncation)]
+        let seg_len = (app1.len() + 2) as u16;
+
+        let mut bytes = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        bytes.extend_from_slice(&seg_len.to_be_bytes());
+        bytes.extend_from_slice(&app1);
+        bytes
+    }
+
+    #[test]
+    fn exif_orientation_reads_little_endian_tiff() {
+        let bytes = jpeg_with_exif_orientation(true, 6);
+        assert_eq!(exif_orientation(&bytes), Some(6));
+    }
+
+    #[test]
+    fn exif_orientation_reads_big_endian_tiff() {
+        let bytes = jpeg_with_exif_orientation(false, 8);
+        assert_eq!(exif_orientation(&bytes), Some(8));
+    }
+
+    #[test]
+    fn exif_orientation_handles_truncated_segment() {
+        let bytes = vec![0xFF, 0xD8, 0xFF, 0xE1, 0x00, 0x10, 0x00, 0x00];
+        assert_eq!(exif_orientation(&bytes), None);
+    }
 }